@@ -1,129 +1,481 @@
-use std::{cmp::Ordering, error::Error};
+use std::{
+    cmp::Ordering,
+    error::Error,
+    future::Future,
+    pin::Pin,
+    time::{Duration, Instant},
+};
 
-use futures::{future::Either, SinkExt, StreamExt};
-use rust_decimal::{prelude::ToPrimitive, Decimal};
-use tokio::{select, sync::broadcast::Sender};
-use tokio_tungstenite::tungstenite::Message;
+use futures::{FutureExt, SinkExt, StreamExt};
+use rand::Rng;
+use rust_decimal::prelude::ToPrimitive;
+use tokio::{
+    sync::{broadcast::Sender, oneshot, watch},
+    time::sleep,
+};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
 
 use crate::{
-    iter_utils::OrderedChainExt,
     service::{Level as SummaryLevel, Summary},
-    venue_protocols::*,
+    venue_protocols::{binance::Binance, bitstamp::Bitstamp, Level, Venue, VenueError},
 };
 
+const BASE_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How many levels to publish on each side of the book by default.
+pub const DEFAULT_DEPTH: usize = 10;
+
+type WsStream = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Tunables for `aggregator_task`, kept separate from its other parameters
+/// since these (unlike the symbol) can reasonably vary per-subscriber.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// Number of levels to publish on each side of the merged book.
+    pub depth: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config { depth: DEFAULT_DEPTH }
+    }
+}
+
 #[derive(Default)]
 struct Book {
     bids: Vec<Level>,
     asks: Vec<Level>,
 }
 
-fn aggregate_levels(
-    bitstamp_levels: &[Level],
-    binance_levels: &[Level],
-    cmp: Ordering,
-) -> Vec<SummaryLevel> {
-    let make_summary_level = |exchange: &str, price: &Decimal, qty: &Decimal| SummaryLevel {
-        exchange: exchange.to_string(),
-        price: price.to_f64().unwrap(),
-        amount: qty.to_f64().unwrap(),
-    };
-
-    bitstamp_levels
-        .iter()
-        .ordered_chain(binance_levels.iter(), cmp)
-        .map(|level| match level {
-            Either::Left((price, qty)) => make_summary_level("bitstamp", price, qty),
-            Either::Right((price, qty)) => make_summary_level("binance", price, qty),
-        })
-        .take(10)
-        .collect()
+/// Tracks the reconnect delay for a single venue, doubling on every failed
+/// attempt (with a little jitter so venues don't all hammer their socket at
+/// the same instant) and resetting once the feed is healthy again.
+struct Backoff {
+    delay: Duration,
 }
 
-pub async fn aggregator_task(symbol: String, tx: Sender<Summary>) -> Result<(), Box<dyn Error>> {
-    let (mut bitstamp_ws, _) = tokio_tungstenite::connect_async("wss://ws.bitstamp.net.").await.expect("Failed to connect to bitstamp websocket");
+impl Backoff {
+    fn new() -> Self {
+        Backoff {
+            delay: BASE_BACKOFF,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.delay = BASE_BACKOFF;
+    }
+
+    async fn wait_and_grow(&mut self) {
+        let jitter = Duration::from_millis(
+            rand::thread_rng().gen_range(0..self.delay.as_millis() as u64 / 4 + 1),
+        );
+        sleep(self.delay + jitter).await;
+        self.delay = (self.delay * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// The live socket for a venue, or the in-flight work needed to get one back.
+///
+/// Reconnects and gap resyncs run on a spawned task rather than being
+/// `.await`ed in place, so a venue stuck backing off (or waiting on a REST
+/// snapshot) never blocks polling the sockets of the other, healthy venues.
+enum Conn {
+    Live(WsStream),
+    Reconnecting(oneshot::Receiver<(Box<dyn Venue>, WsStream, Backoff)>),
+}
+
+/// A connected venue: its feed definition, the live socket (or reconnect
+/// future), the last book we parsed from it, and our reconnect state.
+struct VenueState {
+    /// Cached from `venue.name()` since `venue` is temporarily absent while
+    /// a reconnect or resync is in flight on another task.
+    name: &'static str,
+    /// Cached from `venue.max_staleness()` for the same reason, and because
+    /// it's invariant for the venue's lifetime so there's no need to go
+    /// through `Option<Box<dyn Venue>>` to read it on every round.
+    max_staleness: Duration,
+    venue: Option<Box<dyn Venue>>,
+    conn: Conn,
+    resyncing: Option<oneshot::Receiver<Box<dyn Venue>>>,
+    book: Book,
+    backoff: Backoff,
+    last_update: Instant,
+}
+
+impl VenueState {
+    /// Connects to `venue`, retrying with backoff until it succeeds. The
+    /// task should only stop on an explicit shutdown signal, so a transient
+    /// failure here must not be allowed to end `aggregator_task` on startup.
+    async fn connect(mut venue: Box<dyn Venue>, symbol: &str) -> Self {
+        let name = venue.name();
+        let max_staleness = venue.max_staleness();
+        let mut backoff = Backoff::new();
+
+        let ws = loop {
+            match Self::open_socket(venue.as_ref(), symbol).await {
+                Ok(ws) => {
+                    if let Err(e) = venue.resync(symbol).await {
+                        eprintln!("Failed to resync {name} on initial connect, {e}");
+                    }
+                    break ws;
+                }
+                Err(e) => {
+                    eprintln!("Failed to connect to {name}, {e}, retrying");
+                    backoff.wait_and_grow().await;
+                }
+            }
+        };
+
+        VenueState {
+            name,
+            max_staleness,
+            venue: Some(venue),
+            conn: Conn::Live(ws),
+            resyncing: None,
+            book: Book::default(),
+            backoff,
+            last_update: Instant::now(),
+        }
+    }
+
+    async fn open_socket(venue: &dyn Venue, symbol: &str) -> Result<WsStream, Box<dyn Error>> {
+        let (mut ws, _) = tokio_tungstenite::connect_async(venue.ws_url(symbol)).await?;
+        if let Some(payload) = venue.subscription_payload(symbol) {
+            ws.send(Message::Text(payload)).await?;
+        }
+        Ok(ws)
+    }
 
-    let subscription_message = bitstamp::make_subscription_payload(&symbol);
-    bitstamp_ws
-        .send(Message::Text(subscription_message))
-        .await?;
+    /// Hands the venue off to a spawned task that backs off and reconnects
+    /// (retrying until it succeeds), and switches `conn` over to watch for
+    /// its result instead of polling a dead socket.
+    fn start_reconnect(&mut self, symbol: String) {
+        self.book = Book::default();
+        let Some(venue) = self.venue.take() else {
+            return; // already reconnecting or resyncing
+        };
+        let backoff = std::mem::replace(&mut self.backoff, Backoff::new());
+
+        let (result_tx, result_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let outcome = reconnect_with_backoff(venue, symbol, backoff).await;
+            let _ = result_tx.send(outcome);
+        });
+        self.conn = Conn::Reconnecting(result_rx);
+    }
 
-    let (mut binance_ws, _) = tokio_tungstenite::connect_async(format!(
-        "wss://stream.binance.com:9443/ws/{symbol}@depth20@100ms"
-    ))
-    .await?;
+    /// Hands the venue off to a spawned task that fetches a fresh REST
+    /// snapshot, leaving the socket itself connected and live. Messages that
+    /// arrive while the resync is in flight are dropped, same as before the
+    /// first resync completes.
+    fn start_resync(&mut self, symbol: String) {
+        self.book = Book::default();
+        let Some(mut venue) = self.venue.take() else {
+            return; // already reconnecting or resyncing
+        };
 
-    let mut bitstamp_book = Book::default();
-    let mut binance_book = Book::default();
+        let (result_tx, result_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            if let Err(e) = venue.resync(&symbol).await {
+                eprintln!("Failed to resync {}, {e}", venue.name());
+            }
+            let _ = result_tx.send(venue);
+        });
+        self.resyncing = Some(result_rx);
+    }
+}
 
+/// Backs off and retries connecting `venue` until it succeeds, returning the
+/// venue, its new socket, and the (now-grown) backoff so the caller can keep
+/// tracking delay across any subsequent disconnects.
+async fn reconnect_with_backoff(
+    mut venue: Box<dyn Venue>,
+    symbol: String,
+    mut backoff: Backoff,
+) -> (Box<dyn Venue>, WsStream, Backoff) {
     loop {
-        select! {
-            result = bitstamp_ws.next() => {
-                // println!("Bitstamp {result:?}");
-                match result {
+        backoff.wait_and_grow().await;
+        match VenueState::open_socket(venue.as_ref(), &symbol).await {
+            Ok(ws) => {
+                if let Err(e) = venue.resync(&symbol).await {
+                    eprintln!("Failed to resync {} after reconnect, {e}", venue.name());
+                }
+                return (venue, ws, backoff);
+            }
+            Err(e) => {
+                eprintln!("Failed to reconnect to {}, {e}, retrying", venue.name());
+            }
+        }
+    }
+}
 
-                    Some(Ok(Message::Text(payload))) => {
-                        match serde_json::from_str::<bitstamp::FeedMessage>(&payload) {
-                            Ok(bitstamp::FeedMessage::Data {data, ..}) => {
-                                bitstamp_book.bids = data.bids;
-                                bitstamp_book.asks = data.asks;
+/// What a single venue's polled future resolved to this round, tagged with
+/// its index in `states` so the caller can find it back without the borrow
+/// complications of keeping the future and the state alive at once.
+enum VenuePoll {
+    Message(Option<Result<Message, tokio_tungstenite::tungstenite::Error>>),
+    Reconnected(Result<(Box<dyn Venue>, WsStream, Backoff), oneshot::error::RecvError>),
+    Resynced(Result<Box<dyn Venue>, oneshot::error::RecvError>),
+}
 
-                                // The venue already provides the levels sorted, but lets sort anyway
-                                bitstamp_book.bids.sort_by(|(px_a, _), (px_b, _) | px_b.cmp(px_a));
-                                bitstamp_book.asks.sort_by_key(|(px, _)| *px);
+pub async fn aggregator_task(
+    symbol: String,
+    tx: Sender<Summary>,
+    mut shutdown: watch::Receiver<bool>,
+    config: Config,
+) -> Result<(), Box<dyn Error>> {
+    let venues: Vec<Box<dyn Venue>> = vec![Box::new(Bitstamp), Box::new(Binance::new())];
 
-                            }
-                            Ok(bitstamp::FeedMessage::Error {message, ..}) => panic!("Failed to subscribe to symbol, {message}"),
-                            Err(e) => panic!("Failed to parse bitstamp payload, {payload}, {e}"),
-                            _ => {
-                                continue;
-                            }
-                        }
+    let mut states = Vec::with_capacity(venues.len());
+    for venue in venues {
+        states.push(VenueState::connect(venue, &symbol).await);
+    }
+
+    loop {
+        let futs: Vec<Pin<Box<dyn Future<Output = (usize, VenuePoll)> + Send + '_>>> = states
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(idx, state)| {
+                let mut polls: Vec<Pin<Box<dyn Future<Output = (usize, VenuePoll)> + Send + '_>>> =
+                    Vec::new();
 
-                    },
-                    Some(Err(e)) => panic!("Error from bitstamp socket, {e}"),
-                    None => panic!("Disconnected from bitstamp"),
-                    _ => {}
+                match &mut state.conn {
+                    Conn::Live(ws) => {
+                        polls.push(Box::pin(ws.next().map(move |m| (idx, VenuePoll::Message(m)))));
+                    }
+                    Conn::Reconnecting(rx) => {
+                        polls.push(Box::pin(rx.map(move |r| (idx, VenuePoll::Reconnected(r)))));
+                    }
+                }
+                if let Some(rx) = &mut state.resyncing {
+                    polls.push(Box::pin(rx.map(move |r| (idx, VenuePoll::Resynced(r)))));
                 }
-            },
 
-            result = binance_ws.next() => {
-                // println!("Binance {result:?}");
-                match result {
-                    Some(Ok(Message::Text(payload))) => {
-                        match serde_json::from_str::<binance::BookUpdate>(&payload) {
-                            Ok(update) => {
-                                binance_book.bids = update.bids;
-                                binance_book.asks = update.asks;
-
-                                // The venue already provides the levels sorted, but lets sort anyway
-                                binance_book.bids.sort_by(|(px_a, _), (px_b, _) | px_b.cmp(px_a));
-                                binance_book.asks.sort_by_key(|(px, _)| *px);
+                polls
+            })
+            .collect();
+
+        tokio::select! {
+            ((idx, poll), _ready, _remaining) = futures::future::select_all(futs) => {
+                match poll {
+                    VenuePoll::Message(message) => {
+                        let state = &mut states[idx];
+                        match message {
+                            Some(Ok(Message::Text(payload))) => {
+                                // `venue` is briefly `None` while a resync is in flight on a
+                                // spawned task (see `start_resync`); any message that arrives
+                                // in that window can't be parsed, so drop it rather than
+                                // unwrapping a venue that isn't there.
+                                if let Some(venue) = state.venue.as_mut() {
+                                    match venue.parse_book_update(&payload) {
+                                        Ok(Some(update)) => {
+                                            // `aggregate_levels` sorts the merged levels of all
+                                            // venues, so there's nothing to sort here.
+                                            state.book.bids = update.bids;
+                                            state.book.asks = update.asks;
+
+                                            state.backoff.reset();
+                                            state.last_update = Instant::now();
+                                        }
+                                        Ok(None) => {}
+                                        Err(VenueError::Gap) => {
+                                            eprintln!("{} local book fell out of sync, resyncing", state.name);
+                                            state.start_resync(symbol.clone());
+                                        }
+                                        Err(e) => {
+                                            eprintln!("Failed to parse payload from {}, {payload}, {e}", state.name);
+                                        }
+                                    }
+                                }
+                            }
+                            Some(Ok(_)) => {}
+                            Some(Err(e)) => {
+                                eprintln!("Error from {} socket, {e}, reconnecting", state.name);
+                                state.start_reconnect(symbol.clone());
+                            }
+                            None => {
+                                eprintln!("Disconnected from {}, reconnecting", state.name);
+                                state.start_reconnect(symbol.clone());
                             }
-                            Err(e) => panic!("Failed to parse payload from binance, {payload}, {e}")
                         }
-
-                    },
-                    Some(Err(e)) => panic!("Error from binance socket, {e}"),
-                    None => panic!("Disconnected from binance"),
-                    _ => {
-                        continue;
+                    }
+                    VenuePoll::Reconnected(Ok((venue, ws, backoff))) => {
+                        let state = &mut states[idx];
+                        state.venue = Some(venue);
+                        state.backoff = backoff;
+                        state.conn = Conn::Live(ws);
+                        state.last_update = Instant::now();
+                    }
+                    VenuePoll::Reconnected(Err(_)) => {
+                        eprintln!("{} reconnect task vanished unexpectedly", states[idx].name);
+                    }
+                    VenuePoll::Resynced(Ok(venue)) => {
+                        let state = &mut states[idx];
+                        state.venue = Some(venue);
+                        state.resyncing = None;
+                    }
+                    VenuePoll::Resynced(Err(_)) => {
+                        eprintln!("{} resync task vanished unexpectedly", states[idx].name);
+                        states[idx].resyncing = None;
                     }
                 }
+            },
+
+            _ = shutdown.changed() => {
+                if *shutdown.borrow() {
+                    return Ok(());
+                }
+                continue;
             }
         }
 
-        let bids = aggregate_levels(&bitstamp_book.bids, &binance_book.bids, Ordering::Greater);
-        let asks = aggregate_levels(&bitstamp_book.asks, &binance_book.asks, Ordering::Less);
+        let now = Instant::now();
+        let fresh = states
+            .iter()
+            .filter(|state| now.duration_since(state.last_update) <= state.max_staleness)
+            .map(|state| (state.name, &state.book));
 
-        if bids.is_empty() || asks.is_empty() {
+        let bids = aggregate_levels(fresh.clone(), |book| &book.bids, Ordering::Greater, config.depth);
+        let asks = aggregate_levels(fresh, |book| &book.asks, Ordering::Less, config.depth);
+
+        let Some(summary) = compute_summary(bids, asks) else {
             // We can't publish a spread since the book is one-sided.
             continue;
+        };
+
+        if summary.crossed {
+            eprintln!(
+                "Crossed book for {symbol}, best bid {} >= best ask {}, flagging summary",
+                summary.bids[0].price, summary.asks[0].price
+            );
+        }
+
+        tx.send(summary)?;
+    }
+}
+
+fn aggregate_levels<'a>(
+    books: impl Iterator<Item = (&'a str, &'a Book)> + Clone,
+    side: impl Fn(&Book) -> &Vec<Level>,
+    cmp: Ordering,
+    depth: usize,
+) -> Vec<SummaryLevel> {
+    let mut merged: Vec<SummaryLevel> = books
+        .flat_map(|(name, book)| {
+            side(book).iter().map(move |(price, qty)| SummaryLevel {
+                exchange: name.to_string(),
+                price: price.to_f64().unwrap(),
+                amount: qty.to_f64().unwrap(),
+            })
+        })
+        .collect();
+
+    merged.sort_by(|a, b| match cmp {
+        Ordering::Greater => b.price.partial_cmp(&a.price).unwrap(),
+        Ordering::Less => a.price.partial_cmp(&b.price).unwrap(),
+        Ordering::Equal => Ordering::Equal,
+    });
+
+    merged.truncate(depth);
+    merged
+}
+
+/// Builds the `Summary` to publish from a round's merged levels, or `None` if
+/// the book is one-sided and there's no spread to report. Also responsible
+/// for flagging a crossed book (best bid >= best ask), which can happen
+/// transiently while venues are merged at slightly different points in time.
+fn compute_summary(bids: Vec<SummaryLevel>, asks: Vec<SummaryLevel>) -> Option<Summary> {
+    if bids.is_empty() || asks.is_empty() {
+        return None;
+    }
+
+    let crossed = bids[0].price >= asks[0].price;
+    let spread = asks[0].price - bids[0].price;
+
+    Some(Summary {
+        bids,
+        asks,
+        spread,
+        crossed,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn level(exchange: &str, price: &str, amount: &str) -> SummaryLevel {
+        SummaryLevel {
+            exchange: exchange.to_string(),
+            price: price.parse().unwrap(),
+            amount: amount.parse().unwrap(),
         }
+    }
+
+    fn book(bids: Vec<(&str, &str)>, asks: Vec<(&str, &str)>) -> Book {
+        fn levels(raw: Vec<(&str, &str)>) -> Vec<Level> {
+            raw.into_iter()
+                .map(|(p, q)| (p.parse().unwrap(), q.parse().unwrap()))
+                .collect()
+        }
+        Book {
+            bids: levels(bids),
+            asks: levels(asks),
+        }
+    }
 
-        let spread = asks[0].price - bids[0].price;
+    #[test]
+    fn aggregate_levels_truncates_to_configured_depth() {
+        let a = book(vec![("10", "1"), ("9", "1"), ("8", "1")], vec![]);
+        let b = book(vec![("11", "1"), ("7", "1")], vec![]);
+        let books = [("a", &a), ("b", &b)];
 
-        tx.send(Summary { bids, asks, spread })?;
+        let bids = aggregate_levels(books.iter().copied(), |book| &book.bids, Ordering::Greater, 2);
+
+        assert_eq!(bids.len(), 2);
+        assert_eq!(bids[0].price, 11.0);
+        assert_eq!(bids[1].price, 10.0);
     }
 
+    #[test]
+    fn aggregate_levels_sorts_asks_ascending() {
+        let a = book(vec![], vec![("10", "1"), ("12", "1")]);
+        let b = book(vec![], vec![("11", "1")]);
+        let books = [("a", &a), ("b", &b)];
+
+        let asks = aggregate_levels(books.iter().copied(), |book| &book.asks, Ordering::Less, 10);
+
+        assert_eq!(
+            asks.iter().map(|l| l.price).collect::<Vec<_>>(),
+            vec![10.0, 11.0, 12.0]
+        );
+    }
 
+    #[test]
+    fn compute_summary_flags_crossed_book() {
+        let bids = vec![level("a", "10", "1")];
+        let asks = vec![level("b", "9", "1")];
+
+        let summary = compute_summary(bids, asks).unwrap();
+
+        assert!(summary.crossed);
+    }
+
+    #[test]
+    fn compute_summary_not_crossed_for_normal_book() {
+        let bids = vec![level("a", "9", "1")];
+        let asks = vec![level("b", "10", "1")];
+
+        let summary = compute_summary(bids, asks).unwrap();
+
+        assert!(!summary.crossed);
+        assert_eq!(summary.spread, 1.0);
+    }
+
+    #[test]
+    fn compute_summary_none_when_one_sided() {
+        assert!(compute_summary(vec![], vec![level("a", "1", "1")]).is_none());
+        assert!(compute_summary(vec![level("a", "1", "1")], vec![]).is_none());
+    }
 }
@@ -1,26 +1,46 @@
+use clap::Parser;
 use tonic::Request;
 
-use crate::service::{orderbook_aggregator_client::OrderbookAggregatorClient, Empty};
+use crate::service::{orderbook_aggregator_client::OrderbookAggregatorClient, SubscribeRequest};
 
 pub mod service {
     tonic::include_proto!("orderbook");
 }
 
+#[derive(Parser, Debug)]
+struct Args {
+    #[clap(long, default_value = "ethbtc")]
+    symbol: String,
+
+    /// Levels to request per side. Defaults to the server's own default depth.
+    #[clap(long, default_value_t = 0)]
+    depth: u32,
+}
+
 #[tokio::main]
 async fn main() {
+    let args: Args = Args::parse();
+
     let mut client = OrderbookAggregatorClient::connect("http://[::1]:10000")
         .await
         .unwrap();
 
     let response = client
-        .book_summary(Request::new(Empty::default()))
+        .book_summary(Request::new(SubscribeRequest {
+            symbol: args.symbol,
+            depth: args.depth,
+        }))
         .await
         .unwrap();
     let mut inbound = response.into_inner();
 
     while let Some(book) = inbound.message().await.unwrap() {
         print!("{}[2J", 27 as char);
-        println!("Spread: {} ", book.spread);
+        println!(
+            "Spread: {} {}",
+            book.spread,
+            if book.crossed { "(crossed!)" } else { "" }
+        );
         println!("-------------------------------------------------------------------");
 
         for (bid, ask) in book.bids.iter().zip(book.asks) {
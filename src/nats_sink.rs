@@ -0,0 +1,32 @@
+use prost::Message;
+use tokio::sync::broadcast;
+
+use crate::service::Summary;
+
+/// Republishes every `Summary` broadcast by an `aggregator_task` onto a NATS
+/// subject, encoded as protobuf bytes, for consumers that don't speak gRPC.
+/// Runs until the aggregator's broadcast channel closes.
+pub async fn publish(nats_url: String, subject: String, mut rx: broadcast::Receiver<Summary>) {
+    let client = match async_nats::connect(&nats_url).await {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to connect to NATS at {nats_url}, {e}");
+            return;
+        }
+    };
+
+    loop {
+        let summary = match rx.recv().await {
+            Ok(summary) => summary,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        if let Err(e) = client
+            .publish(subject.clone(), summary.encode_to_vec().into())
+            .await
+        {
+            eprintln!("Failed to publish to NATS subject {subject}, {e}");
+        }
+    }
+}
@@ -1,30 +1,90 @@
 mod aggregator;
-mod iter_utils;
+#[cfg(feature = "nats")]
+mod nats_sink;
 mod venue_protocols;
 
-use std::pin::Pin;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
 
 use clap::Parser;
 use futures_core::Stream;
-use tokio::{
-    select,
-    sync::{broadcast, broadcast::Sender},
-};
+use tokio::sync::{broadcast, broadcast::Sender, watch};
 use tokio_stream::{wrappers::BroadcastStream, StreamExt};
 use tonic::{transport::Server, Response, Status};
 
 use crate::service::{
     orderbook_aggregator_server::{OrderbookAggregator, OrderbookAggregatorServer},
-    Empty, Summary,
+    SubscribeRequest, Summary,
 };
 
 pub mod service {
     tonic::include_proto!("orderbook");
 }
 
+#[derive(Parser, Debug)]
+struct Args {
+    /// NATS server to additionally publish each symbol's Summary onto, e.g.
+    /// `nats://localhost:4222`. Left unset, no NATS publishing happens.
+    #[cfg(feature = "nats")]
+    #[clap(long)]
+    nats_url: Option<String>,
+
+    /// Subjects are published as `<prefix>.<symbol>.<depth>`.
+    #[cfg(feature = "nats")]
+    #[clap(long, default_value = "orderbook")]
+    nats_subject_prefix: String,
+}
+
+#[cfg(feature = "nats")]
 #[derive(Debug)]
-struct Service {
+struct NatsConfig {
+    url: String,
+    subject_prefix: String,
+}
+
+/// One symbol's live aggregator: where to publish updates, how to signal it
+/// to stop, and how many `book_summary` subscribers are still listening.
+#[derive(Debug)]
+struct SymbolEntry {
     tx: Sender<Summary>,
+    shutdown_tx: watch::Sender<bool>,
+    subscriber_count: usize,
+}
+
+/// A distinct symbol/depth pair gets its own aggregator, since depth changes
+/// how much of the book each subscriber needs published.
+type SymbolKey = (String, usize);
+
+type Registry = Arc<Mutex<HashMap<SymbolKey, SymbolEntry>>>;
+
+/// Drops a subscriber's slot in the registry when its `book_summary` stream
+/// ends, tearing the aggregator down once nobody is left watching its key.
+struct SubscriberGuard {
+    registry: Registry,
+    key: SymbolKey,
+}
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        let mut registry = self.registry.lock().unwrap();
+        if let Some(entry) = registry.get_mut(&self.key) {
+            entry.subscriber_count -= 1;
+            if entry.subscriber_count == 0 {
+                let _ = entry.shutdown_tx.send(true);
+                registry.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Service {
+    registry: Registry,
+    #[cfg(feature = "nats")]
+    nats: Option<Arc<NatsConfig>>,
 }
 
 #[tonic::async_trait]
@@ -34,12 +94,94 @@ impl OrderbookAggregator for Service {
 
     async fn book_summary(
         &self,
-        _request: tonic::Request<Empty>,
+        request: tonic::Request<SubscribeRequest>,
     ) -> Result<Response<Self::BookSummaryStream>, Status> {
-        let rx = self.tx.subscribe();
+        let request = request.into_inner();
+        let symbol = request.symbol;
+        let depth = if request.depth == 0 {
+            aggregator::DEFAULT_DEPTH
+        } else {
+            request.depth as usize
+        };
+        let key: SymbolKey = (symbol.clone(), depth);
+
+        let rx = {
+            let mut registry = self.registry.lock().unwrap();
+            match registry.get_mut(&key) {
+                Some(entry) => {
+                    entry.subscriber_count += 1;
+                    entry.tx.subscribe()
+                }
+                None => {
+                    let (tx, rx) = broadcast::channel(16);
+                    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+                    let config = aggregator::Config {
+                        depth,
+                        ..Default::default()
+                    };
+                    let handle = tokio::spawn(aggregator::aggregator_task(
+                        symbol.clone(),
+                        tx.clone(),
+                        shutdown_rx,
+                        config,
+                    ));
+
+                    // aggregator_task only ever exits on an explicit shutdown
+                    // signal (sent by SubscriberGuard::drop once the last
+                    // subscriber goes away) or an unexpected error; either
+                    // way, make sure its registry entry doesn't outlive it so
+                    // the next subscriber spins up a fresh aggregator instead
+                    // of hanging on a dead one. Only remove the entry if it
+                    // still belongs to *this* task: a new subscriber may have
+                    // already raced in and installed a fresh entry under the
+                    // same key by the time this one finishes tearing down.
+                    let supervise_registry = self.registry.clone();
+                    let supervise_key = key.clone();
+                    let supervise_tx = tx.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle.await {
+                            eprintln!("Aggregator task for {supervise_key:?} exited abnormally, {e:?}");
+                        }
+                        let mut registry = supervise_registry.lock().unwrap();
+                        if registry
+                            .get(&supervise_key)
+                            .is_some_and(|entry| entry.tx.same_channel(&supervise_tx))
+                        {
+                            registry.remove(&supervise_key);
+                        }
+                    });
+
+                    #[cfg(feature = "nats")]
+                    if let Some(nats) = &self.nats {
+                        // Subject must include depth: two different-depth
+                        // subscriptions to the same symbol are two distinct
+                        // aggregators and must not collide onto one subject.
+                        let subject = format!("{}.{}.{}", nats.subject_prefix, symbol, depth);
+                        tokio::spawn(nats_sink::publish(nats.url.clone(), subject, tx.subscribe()));
+                    }
+
+                    registry.insert(
+                        key.clone(),
+                        SymbolEntry {
+                            tx,
+                            shutdown_tx,
+                            subscriber_count: 1,
+                        },
+                    );
+                    rx
+                }
+            }
+        };
+
+        let guard = SubscriberGuard {
+            registry: self.registry.clone(),
+            key,
+        };
         let mut stream = BroadcastStream::new(rx);
 
         let output = async_stream::try_stream! {
+            let _guard = guard;
             while let Some(Ok(summary)) = stream.next().await {
                 yield summary
             }
@@ -49,30 +191,40 @@ impl OrderbookAggregator for Service {
     }
 }
 
-#[derive(Parser, Debug)]
-struct Args {
-    #[clap(long, default_value = "ethbtc")]
-    symbol: String,
-}
-
 #[tokio::main]
 async fn main() {
+    #[cfg_attr(not(feature = "nats"), allow(unused_variables))]
     let args: Args = Args::parse();
 
-    let (tx, _rx) = broadcast::channel(16);
+    let registry: Registry = Arc::new(Mutex::new(HashMap::new()));
 
     let addr = "[::1]:10000".parse().unwrap();
 
-    let service = Service { tx: tx.clone() };
+    #[cfg(feature = "nats")]
+    let nats = args.nats_url.map(|url| {
+        Arc::new(NatsConfig {
+            url,
+            subject_prefix: args.nats_subject_prefix,
+        })
+    });
+
+    let service = Service {
+        registry: registry.clone(),
+        #[cfg(feature = "nats")]
+        nats,
+    };
 
     let svc = OrderbookAggregatorServer::new(service);
 
-    select! {
-        e = Server::builder().add_service(svc).serve(addr) => {
-            println!("Server exited, {:?}", e);
-        }
-       e = aggregator::aggregator_task(args.symbol, tx) => {
-            println!("Aggregator exited, {:?}", e);
+    tokio::spawn(async move {
+        let _ = tokio::signal::ctrl_c().await;
+        let registry = registry.lock().unwrap();
+        for entry in registry.values() {
+            let _ = entry.shutdown_tx.send(true);
         }
+    });
+
+    if let Err(e) = Server::builder().add_service(svc).serve(addr).await {
+        println!("Server exited, {:?}", e);
     }
 }